@@ -1,6 +1,7 @@
 use std::vec;
 
 use image::{DynamicImage, Rgba, GenericImage};
+use rayon::prelude::*;
 
 mod math;
 use math::{V3, VectorMath, clamp, max, min};
@@ -8,44 +9,29 @@ use math::{V3, VectorMath, clamp, max, min};
 
 //Settings:
 //TODO: I think we need a scene struct and output settings struct or something like that to organise this better.
+//Default image dimensions, used when a scene file omits an `imsize` directive.
 const IMG_WIDTH_PX:u32 = 2500;
 const IMG_HEIGHT_PX:u32 = 2500;
-const FOCAL_LENGTH:f64 = 10.0;
-const CAMERA_POSITION:Point = Point { x: 0.0, y: 0.0, z: -FOCAL_LENGTH };
-const VIEW_PORT_WIDTH:f64 = 4.0; 
 const FADE_DISTANCE_START:f64 = 1000000.0;
 const FADE_DISTANCE_END:f64 = 2000000.0;
-const SPECULAR_REFLECTION_CONSTANT:f64 = 0.5; //TODO: should (also) be per material, not (only) global
-const DIFFUSE_REFLECTION_CONSTANT:f64 = 0.1; //TODO: should (also) be per material, not (only) global
-const AMBIENT_REFLECTION_CONSTANT:f64 = 0.1; //TODO: should (also) be per material, not (only) global
-const MATERIAL_SHININESS_CONSTANT:f64 = 1.5; //TODO: should (also) be per material, not (only) global
+const MAX_RECURSION_DEPTH:u32 = 5;
 const COLOR_MODE:ColorMode = ColorMode::Light;
+//Footprint of a single pixel in the sample-position units used by Camera::ray_for_sample.
+const PIX_SIZE_X:f64 = 1.0;
+const PIX_SIZE_Y:f64 = 1.0;
+//Jittered sub-samples averaged per pixel to anti-alias edges.
+const SAMPLES_PER_PIXEL:u32 = 16;
 
 
 #[allow(dead_code)]
 enum ColorMode {
     StaticColor,
     Normals,
-    Light
+    Light,
+    PathTrace,
 }
 
 
-const VIEW_PORT_HEIGHT:f64 = (IMG_HEIGHT_PX as f64 / IMG_WIDTH_PX as f64) * VIEW_PORT_WIDTH;
-const VIEW_PORT_TOP_LEFT:Point = Point { x: -(VIEW_PORT_WIDTH / 2.0), y: -(VIEW_PORT_HEIGHT / 2.0), z: 0.0};
-
-const PIX_SIZE_X:f64 = VIEW_PORT_WIDTH / IMG_WIDTH_PX as f64;
-const PIX_SIZE_Y:f64 = VIEW_PORT_HEIGHT / IMG_HEIGHT_PX as f64;
-const PIX_X_Y_RATIO_IS_SANE:bool = PIX_SIZE_X - PIX_SIZE_Y < 0.001 && PIX_SIZE_X - PIX_SIZE_Y > -0.001;
-
-#[allow(dead_code)] const fn check_viewport_is_sane() {
-    //This function is not actually dead code, but its compile-time only
-    if !PIX_X_Y_RATIO_IS_SANE {
-        panic!("viewport scaling is not correct!");
-    }
-}
-const _: () = check_viewport_is_sane();
-
-
 #[allow(dead_code)] const COLOR_BLACK:Color = Color {r: 0.0, g: 0.0, b: 0.0};
 #[allow(dead_code)] const COLOR_RED:Color = Color {r: 255.0, g: 0.0, b: 0.0};
 #[allow(dead_code)] const COLOR_GREEN:Color = Color {r: 0.0, g: 255.0, b: 0.0};
@@ -59,6 +45,7 @@ const _: () = check_viewport_is_sane();
 
 trait Intersectable {
     fn intersect(&self, ray: &Ray) -> Option<Hit>;
+    fn bounding_box(&self) -> Aabb;
 }
 
 #[derive(Clone, Debug)]
@@ -110,17 +97,46 @@ struct Ray {
     direction: Direction
 }
 
+//The shading parameters of a surface. Replaces the old global *_REFLECTION_CONSTANT constants so a
+//scene can mix e.g. a shiny plastic sphere, a matte triangle and a mirror.
+#[derive(Clone)]
+struct Material {
+    color: Color,
+    diffuse: f64,
+    specular: f64,
+    ambient: f64,
+    shininess: f64,
+    reflectivity: f64,
+    index_of_refraction: f64,
+}
+
+impl Material {
+    //Defaults matching the reflection constants that used to be global.
+    fn new(color: Color) -> Material {
+        return Material { color, diffuse: 0.1, specular: 0.5, ambient: 0.1, shininess: 1.5,
+                          reflectivity: 0.0, index_of_refraction: 1.0 };
+    }
+}
+
 struct Sphere {
     center: Point,
     radius: f64,
-    color: Color,
+    material: Material,
 }
 
 struct Triangle {
     p1: Point,
     p2: Point,
     p3: Point,
-    color: Color,
+    material: Material,
+}
+
+//Unbounded plane through `point` with the given `normal`, for floors/walls that would otherwise
+//need a large triangle grid.
+struct Plane {
+    point: Point,
+    normal: Direction,
+    material: Material,
 }
 
 struct Light {
@@ -131,17 +147,222 @@ struct Light {
 
 struct Hit {
     point: Point,
-    material_color: Color,
+    material: Material,
     distance: f64,
     surface_normal: Direction,
 }
 
+//Axis-aligned bounding box, used to build the BVH below.
+#[derive(Clone)]
+struct Aabb {
+    min: Point,
+    max: Point,
+}
+
+impl Aabb {
+    fn union(&self, other: &Aabb) -> Aabb {
+        return Aabb {
+            min: Point { x: self.min.x.min(other.min.x), y: self.min.y.min(other.min.y), z: self.min.z.min(other.min.z) },
+            max: Point { x: self.max.x.max(other.max.x), y: self.max.y.max(other.max.y), z: self.max.z.max(other.max.z) },
+        };
+    }
+
+    fn centroid(&self) -> Point {
+        return self.min.add(&self.max).multiply(0.5);
+    }
+
+    //Slab test: for each axis, clip [tmin, tmax] against where the ray enters/exits that axis'
+    //pair of planes. If the interval ever becomes empty, the ray missed the box.
+    fn intersects(&self, ray: &Ray) -> bool {
+        let mut tmin = 0.0_f64;
+        let mut tmax = f64::MAX;
+
+        for axis in 0..3 {
+            let (origin, direction, axis_min, axis_max) = match axis {
+                0 => (ray.origin.x, ray.direction.x, self.min.x, self.max.x),
+                1 => (ray.origin.y, ray.direction.y, self.min.y, self.max.y),
+                _ => (ray.origin.z, ray.direction.z, self.min.z, self.max.z),
+            };
+
+            let inv_direction = 1.0 / direction;
+            let mut t1 = (axis_min - origin) * inv_direction;
+            let mut t2 = (axis_max - origin) * inv_direction;
+            if inv_direction < 0.0 {
+                std::mem::swap(&mut t1, &mut t2);
+            }
+
+            tmin = tmin.max(t1);
+            tmax = tmax.min(t2);
+            if tmax < tmin {
+                return false;
+            }
+        }
+
+        return tmax >= tmin.max(0.0);
+    }
+}
+
 enum Object {
     SphereObject(Sphere),
     TriangleObject(Triangle),
+    PlaneObject(Plane),
     LightObject(Light),
 }
 
+fn object_bounding_box(object: &Object) -> Aabb {
+    match object {
+        Object::SphereObject(x) => x.bounding_box(),
+        Object::TriangleObject(x) => x.bounding_box(),
+        Object::PlaneObject(x) => x.bounding_box(),
+        Object::LightObject(_) => panic!("lights do not have a bounding box and should never end up in the BVH"),
+    }
+}
+
+fn object_intersect(object: &Object, ray: &Ray) -> Option<Hit> {
+    match object {
+        Object::SphereObject(x) => x.intersect(ray),
+        Object::TriangleObject(x) => x.intersect(ray),
+        Object::PlaneObject(x) => x.intersect(ray),
+        Object::LightObject(_) => None,
+    }
+}
+
+//Bounding Volume Hierarchy over the scene's spheres, triangles and planes (lights are never stored in
+//it, see object_bounding_box), so send_ray only tests primitives whose box the ray actually
+//enters instead of scanning every object in the scene.
+enum BvhNode {
+    Leaf { bounds: Aabb, object_indices: Vec<usize> },
+    Split { bounds: Aabb, left: Box<BvhNode>, right: Box<BvhNode> },
+}
+
+impl BvhNode {
+    fn bounds(&self) -> &Aabb {
+        return match self {
+            BvhNode::Leaf { bounds, .. } => bounds,
+            BvhNode::Split { bounds, .. } => bounds,
+        };
+    }
+}
+
+//Primitives per leaf; below this count the O(n) scan inside the leaf beats further splitting.
+const BVH_MAX_LEAF_SIZE: usize = 4;
+
+//Recursively splits `indices` along the longest axis of their combined bounds at the median
+//centroid, top-down, until each leaf holds at most BVH_MAX_LEAF_SIZE primitives.
+fn build_bvh(objects: &Vec<Object>, mut indices: Vec<usize>) -> BvhNode {
+    let bounds = indices.iter()
+        .map(|&i| object_bounding_box(&objects[i]))
+        .reduce(|a, b| a.union(&b))
+        .unwrap_or(Aabb { min: Point { x: 0.0, y: 0.0, z: 0.0 }, max: Point { x: 0.0, y: 0.0, z: 0.0 } });
+
+    if indices.len() <= BVH_MAX_LEAF_SIZE {
+        return BvhNode::Leaf { bounds, object_indices: indices };
+    }
+
+    let extent = bounds.max.subtract(&bounds.min);
+    let split_on_x = extent.x >= extent.y && extent.x >= extent.z;
+    let split_on_y = !split_on_x && extent.y >= extent.z;
+
+    indices.sort_by(|&a, &b| {
+        let centroid_a = object_bounding_box(&objects[a]).centroid();
+        let centroid_b = object_bounding_box(&objects[b]).centroid();
+        let (value_a, value_b) = if split_on_x { (centroid_a.x, centroid_b.x) }
+                                  else if split_on_y { (centroid_a.y, centroid_b.y) }
+                                  else { (centroid_a.z, centroid_b.z) };
+        return value_a.partial_cmp(&value_b).unwrap();
+    });
+
+    let right_indices = indices.split_off(indices.len() / 2);
+    let left = Box::new(build_bvh(objects, indices));
+    let right = Box::new(build_bvh(objects, right_indices));
+
+    return BvhNode::Split { bounds, left, right };
+}
+
+//Traverses the BVH, only calling intersect() on primitives whose leaf box the ray enters, and
+//keeps the closest hit across both children of a split.
+fn bvh_intersect(node: &BvhNode, objects: &Vec<Object>, ray: &Ray) -> Option<Hit> {
+    if !node.bounds().intersects(ray) {
+        return None;
+    }
+
+    return match node {
+        BvhNode::Leaf { object_indices, .. } => {
+            let mut closest_hit: Option<Hit> = None;
+            for &index in object_indices {
+                if let Some(hit) = object_intersect(&objects[index], ray) {
+                    if closest_hit.as_ref().map_or(true, |closest| hit.distance < closest.distance) {
+                        closest_hit = Some(hit);
+                    }
+                }
+            }
+            closest_hit
+        },
+        BvhNode::Split { left, right, .. } => {
+            match (bvh_intersect(left, objects, ray), bvh_intersect(right, objects, ray)) {
+                (Some(l), Some(r)) => if l.distance < r.distance { Some(l) } else { Some(r) },
+                (Some(l), None) => Some(l),
+                (None, Some(r)) => Some(r),
+                (None, None) => None,
+            }
+        },
+    };
+}
+
+//Everything that used to live in the compile-time VIEW_PORT_*/CAMERA_POSITION constants, now
+//derived at runtime from a scene description file (see parse_scene_file).
+struct SceneConfig {
+    eye: Point,
+    viewdir: Direction,
+    updir: Direction,
+    hfov: f64,
+    img_width: u32,
+    img_height: u32,
+    bkgcolor: Color,
+    objects: Vec<Object>,
+    bvh: BvhNode,
+}
+
+//The orthonormal viewing frame plus the view-port half extents, precomputed once from a SceneConfig.
+struct Camera {
+    eye: Point,
+    forward: Direction,
+    right: Direction,
+    up: Direction,
+    half_width: f64,
+    half_height: f64,
+    img_width: u32,
+    img_height: u32,
+}
+
+impl Camera {
+    fn new(config: &SceneConfig) -> Camera {
+        let forward = config.viewdir.normalize();
+        let right = forward.cross(&config.updir).normalize();
+        let up = right.cross(&forward).normalize();
+
+        //View port sits one unit in front of the eye; hfov fixes its horizontal half width.
+        let half_width = (config.hfov.to_radians() / 2.0).tan();
+        let aspect_ratio = config.img_width as f64 / config.img_height as f64;
+        let half_height = half_width / aspect_ratio;
+
+        return Camera { eye: config.eye.clone(), forward, right, up, half_width, half_height,
+                        img_width: config.img_width, img_height: config.img_height };
+    }
+
+    //Build a ray through the given (possibly sub-pixel) sample position, measured in pixels with
+    //the origin in the top-left corner of the image.
+    fn ray_for_sample(&self, sample_x: f64, sample_y: f64) -> Ray {
+        let offset_right = ((sample_x / self.img_width as f64) * 2.0 - 1.0) * self.half_width;
+        let offset_up = (1.0 - (sample_y / self.img_height as f64) * 2.0) * self.half_height;
+
+        let view_port_point = self.eye.add(&self.forward)
+                                      .add(&self.right.multiply(offset_right))
+                                      .add(&self.up.multiply(offset_up));
+        return ray_through_points(self.eye.clone(), view_port_point);
+    }
+}
+
 
 impl Intersectable for Sphere {
     fn intersect(&self, ray: &Ray) -> Option<Hit> {
@@ -158,13 +379,32 @@ impl Intersectable for Sphere {
 
         let solution1 = (-b + discriminant.sqrt()) / 2.0 * a;
         let solution2 = (-b - discriminant.sqrt()) / 2.0 * a;
-        let closest_solution = min(solution1, solution2, f64::MAX);
+
+        //Reject roots at/behind the ray origin, like Triangle/Plane already do, so a ray leaving
+        //this sphere's own surface (shadow rays, reflection/refraction/path-trace bounces) doesn't
+        //immediately re-hit itself via a root that floating-point error nudged just past zero.
+        const SELF_INTERSECTION_EPSILON: f64 = 1e-6;
+        let closest_solution = if solution1 > SELF_INTERSECTION_EPSILON && solution2 > SELF_INTERSECTION_EPSILON {
+            min(solution1, solution2, f64::MAX)
+        } else if solution1 > SELF_INTERSECTION_EPSILON {
+            solution1
+        } else if solution2 > SELF_INTERSECTION_EPSILON {
+            solution2
+        } else {
+            return None;
+        };
+
         let intersection = ray.origin.add(&ray.direction.multiply(closest_solution));
         let distance = intersection.subtract(&ray.origin).length();
 
         let normal = intersection.subtract(&self.center).normalize();
 
-        return Some( Hit { point: intersection, material_color: self.color.clone(), distance: distance, surface_normal: normal } );
+        return Some( Hit { point: intersection, material: self.material.clone(), distance: distance, surface_normal: normal } );
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let radius_vec = Point { x: self.radius, y: self.radius, z: self.radius };
+        return Aabb { min: self.center.subtract(&radius_vec), max: self.center.add(&radius_vec) };
     }
 }
 
@@ -221,74 +461,203 @@ impl Intersectable for Triangle {
         if !points_are_on_same_side_of_ray(&intersection, &self.p3, &self.p1, &self.p2) { return None }
 
         return Some(Hit {
-            material_color: self.color.clone(),
+            material: self.material.clone(),
             distance: intersection.subtract(&ray.origin).length(),
             point: intersection,
             surface_normal: normal.normalize(),
         });
     }
+
+    fn bounding_box(&self) -> Aabb {
+        return Aabb {
+            min: Point { x: min(self.p1.x, self.p2.x, self.p3.x), y: min(self.p1.y, self.p2.y, self.p3.y), z: min(self.p1.z, self.p2.z, self.p3.z) },
+            max: Point { x: max(self.p1.x, self.p2.x, self.p3.x), y: max(self.p1.y, self.p2.y, self.p3.y), z: max(self.p1.z, self.p2.z, self.p3.z) },
+        };
+    }
+}
+
+impl Intersectable for Plane {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let normal = self.normal.normalize();
+        let denom = ray.direction.dot(&normal);
+        if denom.abs() < 1e-9 {
+            //Ray runs parallel to the plane.
+            return None;
+        }
+
+        let distance_along_ray = self.point.subtract(&ray.origin).dot(&normal) / denom;
+        if distance_along_ray <= 0.0 {
+            //This is behind the view port.
+            return None;
+        }
+
+        //Face the normal towards the ray, like the triangle normal already faces whichever side it was wound for.
+        let facing_normal = if denom > 0.0 { normal.multiply(-1.0) } else { normal };
+
+        let intersection = ray.origin.add(&ray.direction.multiply(distance_along_ray));
+        let distance = intersection.subtract(&ray.origin).length();
+
+        return Some(Hit {
+            point: intersection,
+            material: self.material.clone(),
+            distance,
+            surface_normal: facing_normal,
+        });
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        //Tight on the axis the normal is parallel to (every point on the plane shares that
+        //coordinate there, e.g. a (0,1,0) floor normal pins min.y == max.y == point.y), unbounded
+        //on the others. A plane not aligned with an axis spans that axis too, so its box is
+        //unbounded on all three. This keeps an axis-aligned floor/wall out of BVH leaves a ray
+        //can't geometrically reach instead of spanning all of space unconditionally.
+        let normal = self.normal.normalize();
+
+        let bound_for_axis = |point_component: f64, normal_component: f64| -> (f64, f64) {
+            if (normal_component.abs() - 1.0).abs() < 1e-9 {
+                (point_component, point_component)
+            } else {
+                (-f64::MAX, f64::MAX)
+            }
+        };
+
+        let (min_x, max_x) = bound_for_axis(self.point.x, normal.x);
+        let (min_y, max_y) = bound_for_axis(self.point.y, normal.y);
+        let (min_z, max_z) = bound_for_axis(self.point.z, normal.z);
+
+        return Aabb {
+            min: Point { x: min_x, y: min_y, z: min_z },
+            max: Point { x: max_x, y: max_y, z: max_z },
+        };
+    }
 }
 
 fn ray_through_points(start: Point, end: Point) -> Ray {
     return Ray { direction: end.subtract(&start).normalize(), origin: start }
 }
 
-fn get_color_for_hitpoint(hit: Hit, scene: &Vec<Object>) -> Color {
+fn is_occluded(point: &Point, light_position: &Point, config: &SceneConfig) -> bool {
+    let shadow_ray = ray_through_points(point.clone(), light_position.clone());
+    let distance_to_light = light_position.subtract(&point).length();
 
-    let computed_color = match COLOR_MODE {
-        ColorMode::StaticColor => {
-            COLOR_RED
-        },
-        ColorMode::Normals => {
-            Color {r: (hit.surface_normal.x + 1.0) * 127.5,
-                   g: (hit.surface_normal.y + 1.0) * 127.5,
-                   b: (hit.surface_normal.z + 1.0) * 127.5}
-        },
-        ColorMode::Light => {
-            let mut resulting_light = Color {r: 0.0, g: 0.0, b: 0.0};
-            let mut all_light_sources_summed:Color = Color { r: 0.0, g: 0.0, b: 0.0 };
+    if let Some(hit) = bvh_intersect(&config.bvh, &config.objects, &shadow_ray) {
+        if hit.distance > 0.0 && hit.distance < distance_to_light {
+            return true;
+        }
+    }
+
+    return false;
+}
 
-            for obj in scene.iter() {
+//Direct Phong lighting from the scene's point lights, shared by ColorMode::Light and as the
+//direct term of ColorMode::PathTrace's lighting estimate.
+fn direct_lighting(hit: &Hit, config: &SceneConfig) -> Color {
+    let mut resulting_light = Color {r: 0.0, g: 0.0, b: 0.0};
+    let mut all_light_sources_summed:Color = Color { r: 0.0, g: 0.0, b: 0.0 };
 
-                match obj {
-                    Object::LightObject(light_object) => {
+    for obj in config.objects.iter() {
 
-                        //TODO: not sure if I need both specular and diffuse here?
-                        all_light_sources_summed = all_light_sources_summed.add(&light_object.diffuse_component);
-                        all_light_sources_summed = all_light_sources_summed.add(&light_object.specular_component);
+        match obj {
+            Object::LightObject(light_object) => {
 
-                        let vec_to_light_source = light_object.position.subtract(&hit.point).normalize();
-                        let l_dot_n = vec_to_light_source.dot(&hit.surface_normal);
+                //TODO: not sure if I need both specular and diffuse here?
+                all_light_sources_summed = all_light_sources_summed.add(&light_object.diffuse_component);
+                all_light_sources_summed = all_light_sources_summed.add(&light_object.specular_component);
 
-                        let diffuse_light_part = light_object.diffuse_component.multiply(DIFFUSE_REFLECTION_CONSTANT * l_dot_n);
-                        let color_before_lighting = &hit.material_color;
+                //Cast a shadow ray towards the light; if any geometry sits between the hit point and
+                //the light, only the ambient term contributes for this light.
+                let shadow_origin = hit.point.add(&hit.surface_normal.multiply(1e-4));
+                if is_occluded(&shadow_origin, &light_object.position, config) {
+                    continue;
+                }
 
-                        let diffuse_part = diffuse_light_part.relative_element_wise_multiply(&color_before_lighting);
+                let vec_to_light_source = light_object.position.subtract(&hit.point).normalize();
+                let l_dot_n = vec_to_light_source.dot(&hit.surface_normal);
 
-                        let v_to_camera = CAMERA_POSITION.subtract(&hit.point).normalize();
-                        let reflected_ray_direction = hit.surface_normal.multiply(l_dot_n * 2.0).subtract(&vec_to_light_source).normalize();
-                        let r_dot_v = reflected_ray_direction.dot(&v_to_camera);
+                let diffuse_light_part = light_object.diffuse_component.multiply(hit.material.diffuse * l_dot_n);
+                let color_before_lighting = &hit.material.color;
 
-                        resulting_light = resulting_light.add(&diffuse_part);
+                let diffuse_part = diffuse_light_part.relative_element_wise_multiply(&color_before_lighting);
 
-                        if r_dot_v > 0.0 {
-                            let specular_part = light_object.specular_component.multiply((SPECULAR_REFLECTION_CONSTANT * r_dot_v).powf(MATERIAL_SHININESS_CONSTANT));
-                            resulting_light = resulting_light.add(&specular_part);
-                        }
+                let v_to_camera = config.eye.subtract(&hit.point).normalize();
+                let reflected_ray_direction = hit.surface_normal.multiply(l_dot_n * 2.0).subtract(&vec_to_light_source).normalize();
+                let r_dot_v = reflected_ray_direction.dot(&v_to_camera);
 
-                        //TODO: do I somehow need to scale the light with the number of lights? (not generally, but maybe some overal light scaling to make tuning easier)
-                            //and I need to be able to set (or automatically determine) the sensitivity of the camera (i.e. how we map back to 0-255 for colors)
+                resulting_light = resulting_light.add(&diffuse_part);
 
-                    },
-                    _ => {}
+                if r_dot_v > 0.0 {
+                    let specular_part = light_object.specular_component.multiply((hit.material.specular * r_dot_v).powf(hit.material.shininess));
+                    resulting_light = resulting_light.add(&specular_part);
                 }
 
-            }
+                //TODO: do I somehow need to scale the light with the number of lights? (not generally, but maybe some overal light scaling to make tuning easier)
+                    //and I need to be able to set (or automatically determine) the sensitivity of the camera (i.e. how we map back to 0-255 for colors)
+
+            },
+            _ => {}
+        }
+
+    }
+
+    let ambient_part = hit.material.color.multiply(hit.material.ambient);
+    resulting_light = resulting_light.add(&ambient_part);
+
+    return resulting_light;
+}
+
+//Uniformly distributed direction on the hemisphere around `normal`: draw u1,u2 in [0,1), set z = u1,
+//r = sqrt(1-z^2), phi = 2*pi*u2 for a local sample, then rotate it into the normal's frame.
+fn sample_hemisphere(normal: &Direction) -> Direction {
+    let u1 = rand::random::<f64>();
+    let u2 = rand::random::<f64>();
+
+    let z = u1;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * u2;
+    let local_direction = Direction { x: r * phi.cos(), y: r * phi.sin(), z };
+
+    //Arbitrary vector not parallel to normal, so the cross products below don't degenerate.
+    let arbitrary_up = if normal.x.abs() > 0.9 { Direction { x: 0.0, y: 1.0, z: 0.0 } } else { Direction { x: 1.0, y: 0.0, z: 0.0 } };
+    let tangent = arbitrary_up.cross(normal).normalize();
+    let bitangent = normal.cross(&tangent);
+
+    let world_direction = tangent.multiply(local_direction.x)
+        .add(&bitangent.multiply(local_direction.y))
+        .add(&normal.multiply(local_direction.z));
+
+    return world_direction.normalize();
+}
 
-            let ambient_part = hit.material_color.multiply(AMBIENT_REFLECTION_CONSTANT);
-            resulting_light = resulting_light.add(&ambient_part);
+fn get_color_for_hitpoint(hit: &Hit, config: &SceneConfig, depth: u32) -> Color {
 
-            resulting_light
+    let computed_color = match COLOR_MODE {
+        ColorMode::StaticColor => {
+            COLOR_RED
+        },
+        ColorMode::Normals => {
+            Color {r: (hit.surface_normal.x + 1.0) * 127.5,
+                   g: (hit.surface_normal.y + 1.0) * 127.5,
+                   b: (hit.surface_normal.z + 1.0) * 127.5}
+        },
+        ColorMode::Light => {
+            direct_lighting(hit, config)
+        },
+        ColorMode::PathTrace => {
+            let direct = direct_lighting(hit, config);
+
+            //One indirect bounce over a uniform hemisphere sample, weighted by the
+            //surface albedo; recursing (rather than terminating here) is what lets light bleed
+            //between surfaces. Bounded by MAX_RECURSION_DEPTH like the reflection/refraction rays.
+            let indirect = if depth < MAX_RECURSION_DEPTH {
+                let bounce_direction = sample_hemisphere(&hit.surface_normal);
+                let bounce_origin = hit.point.add(&hit.surface_normal.multiply(1e-4));
+                let bounce_ray = Ray { origin: bounce_origin, direction: bounce_direction };
+                send_ray(config, &bounce_ray, depth + 1).relative_element_wise_multiply(&hit.material.color)
+            } else {
+                COLOR_BLACK
+            };
+
+            direct.add(&indirect)
         }
     };
 
@@ -303,85 +672,222 @@ fn get_color_for_hitpoint(hit: Hit, scene: &Vec<Object>) -> Color {
 }
 
 
-fn send_ray(scene: &Vec<Object>, ray: &Ray) -> Color {
-    let mut closest_hit_distance = std::f64::MAX;
-    let mut closest_hit:Option<Hit> = None;
+fn send_ray(config: &SceneConfig, ray: &Ray, depth: u32) -> Color {
+    if depth > MAX_RECURSION_DEPTH {
+        return COLOR_BLACK;
+    }
 
-    for obj in scene.iter() {
-        let opt_hit: Option<Hit> = match obj {
-            Object::SphereObject(x) => { x.intersect(&ray) }
-            Object::TriangleObject(x) => { x.intersect(&ray) }
-            Object::LightObject(_) => { None }
-        };
+    let hit = match bvh_intersect(&config.bvh, &config.objects, &ray) {
+        Some(hit) => hit,
+        None => return config.bkgcolor.clone(),
+    };
 
-        match opt_hit {
-            Some(hit) => {
-                if hit.distance < closest_hit_distance {
-                    closest_hit_distance = hit.distance;
-                    closest_hit = Some(hit);
-                }
-            },
-            _ => {}
-        }
+    let local_color = get_color_for_hitpoint(&hit, config, depth);
+
+    if let ColorMode::PathTrace = COLOR_MODE {
+        //Path tracing already folded its indirect bounce into local_color via its own recursive
+        //send_ray call above, so there is nothing left to mix in from the Whitted-style
+        //reflection/refraction handling below.
+        return local_color;
+    }
+
+    if hit.material.reflectivity <= 0.0 && hit.material.index_of_refraction == 1.0 {
+        //Purely diffuse surface, no secondary rays needed.
+        return local_color;
     }
 
-    return match closest_hit {
-        Some(hit) => get_color_for_hitpoint(hit, &scene),
-        _ =>  COLOR_BLACK
+    //Decide which medium we are coming from and going into. If the ray hits the back of the
+    //surface (cos_i < 0) we are leaving the object, so flip the normal and swap the media.
+    let incident = ray.direction.normalize();
+    let mut normal = hit.surface_normal.clone();
+    let mut cos_i = -incident.dot(&normal);
+    let (n1, n2) = if cos_i < 0.0 {
+        normal = normal.multiply(-1.0);
+        cos_i = -cos_i;
+        (hit.material.index_of_refraction, 1.0)
+    } else {
+        (1.0, hit.material.index_of_refraction)
     };
+
+    //Reflected ray, offset along the (possibly flipped) normal to avoid self-intersection acne.
+    //For curved surfaces the offset alone isn't enough to rule out a self-hit (a sphere's
+    //quadratic can still produce a root just past zero), so Sphere::intersect also rejects
+    //near-zero/negative roots itself -- without that, a mirror/glass sphere bounces off its own
+    //surface instead of leaving it, and renders as a black disc once MAX_RECURSION_DEPTH is hit.
+    let reflected_direction = incident.subtract(&normal.multiply(2.0 * incident.dot(&normal))).normalize();
+    let reflected_origin = hit.point.add(&normal.multiply(1e-4));
+    let reflected_color = send_ray(config, &Ray { origin: reflected_origin, direction: reflected_direction }, depth + 1);
+
+    //Schlick-Fresnel reflectance.
+    let f0_root = (n1 - n2) / (n1 + n2);
+    let f0 = f0_root * f0_root;
+    let fresnel = f0 + (1.0 - f0) * (1.0 - cos_i).powi(5);
+
+    if hit.material.index_of_refraction != 1.0 {
+        //Dielectric: split between the reflected and transmitted ray according to Snell's law.
+        let eta = n1 / n2;
+        let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+        if k < 0.0 {
+            //Total internal reflection, there is no transmitted ray.
+            return reflected_color;
+        }
+        let transmitted_direction = incident.multiply(eta)
+            .add(&normal.multiply(eta * cos_i - k.sqrt())).normalize();
+        let transmitted_origin = hit.point.subtract(&normal.multiply(1e-4));
+        let transmitted_color = send_ray(config, &Ray { origin: transmitted_origin, direction: transmitted_direction }, depth + 1);
+        return reflected_color.lerp(&transmitted_color, 1.0 - fresnel);
+    }
+
+    //Opaque reflector: Fresnel boosts the reflectivity towards the grazing edges.
+    let mix = clamp(hit.material.reflectivity + (1.0 - hit.material.reflectivity) * fresnel, 0.0, 1.0);
+    return local_color.lerp(&reflected_color, mix);
 }
 
 
-fn main() {
-    let progress_print_interval = if IMG_WIDTH_PX > 1000 { 100 } else { 10 };
+//Averages SAMPLES_PER_PIXEL jittered rays across the pixel's footprint to anti-alias edges.
+fn sample_pixel(config: &SceneConfig, camera: &Camera, pixel_x: u32, pixel_y: u32) -> Color {
+    let mut summed_color = Color { r: 0.0, g: 0.0, b: 0.0 };
 
-    let scene:Vec<Object> = vec![
-        Object::SphereObject(Sphere { center: Point { x: 15.0, y: 15.0, z: 150.0 }, radius: 5.0, color: COLOR_GREEN }),
-        Object::SphereObject(Sphere { center: Point { x: 15.0, y: 15.0, z: 180.0 }, radius: 5.0, color: COLOR_RED }),
-        Object::SphereObject(Sphere { center: Point { x: 15.0, y: 15.0, z: 210.0 }, radius: 5.0, color: COLOR_GREEN }),
-        Object::SphereObject(Sphere { center: Point { x: 15.0, y: 15.0, z: 240.0 }, radius: 5.0, color: COLOR_RED }),
-        Object::SphereObject(Sphere { center: Point { x: 15.0, y: 15.0, z: 270.0 }, radius: 5.0, color: COLOR_GREEN }),
+    for _ in 0..SAMPLES_PER_PIXEL {
+        let sample_x = pixel_x as f64 + rand::random::<f64>() * PIX_SIZE_X;
+        let sample_y = pixel_y as f64 + rand::random::<f64>() * PIX_SIZE_Y;
+        let ray = camera.ray_for_sample(sample_x, sample_y);
+        summed_color = summed_color.add(&send_ray(config, &ray, 0));
+    }
 
-        Object::TriangleObject(Triangle {p1: Point {x: -10.0, y: -15.0, z: 151.0},
-                                         p2: Point {x: -15.0, y: -15.0, z: 150.0},
-                                         p3: Point {x: -15.0, y: -10.0, z: 150.0}, color: COLOR_BROWN}),
+    return summed_color.multiply(1.0 / SAMPLES_PER_PIXEL as f64);
+}
 
-        Object::TriangleObject(Triangle {p1: Point {x: -10.0, y: 0.0, z: 150.0},
-                                         p2: Point {x: -15.0, y: 0.0, z: 250.0},
-                                         p3: Point {x: -15.0, y: 5.0, z: 250.0}, color: COLOR_BROWN}),
 
-        Object::TriangleObject(Triangle {p1: Point {x: -10.0, y: 10.0, z: 150.0},
-                                         p2: Point {x: -15.0, y: 10.0, z: 151.0},
-                                         p3: Point {x: -15.0, y: 15.0, z: 150.0}, color: COLOR_BROWN}),
+fn parse_scene_file(path: &str) -> SceneConfig {
+    let contents = std::fs::read_to_string(path)
+        .unwrap_or_else(|_| panic!("could not read scene file: {}", path));
+
+    //Sensible defaults so a partial scene file still renders something.
+    let mut eye = Point { x: 0.0, y: 0.0, z: 0.0 };
+    let mut viewdir = Direction { x: 0.0, y: 0.0, z: 1.0 };
+    let mut updir = Direction { x: 0.0, y: 1.0, z: 0.0 };
+    let mut hfov = 90.0;
+    let mut img_width = IMG_WIDTH_PX;
+    let mut img_height = IMG_HEIGHT_PX;
+    let mut bkgcolor = COLOR_BLACK;
+
+    //The material applied to objects is the most recently declared one (like OpenGL state).
+    let mut current_material = Material::new(COLOR_WHITE);
+    let mut vertices: Vec<Point> = vec![];
+    let mut objects: Vec<Object> = vec![];
+
+    for line in contents.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            continue;
+        }
 
+        let number = |index: usize| -> f64 {
+            if index >= tokens.len() {
+                panic!("missing value(s) in scene file line: {}", line);
+            }
+            tokens[index].parse().unwrap_or_else(|_| panic!("invalid number in scene file line: {}", line))
+        };
 
-        Object::LightObject(Light {position: Point { x: -100.0, y: -100.0, z: 0.0 },
-                                   diffuse_component: Color {r: 255.0, g: 255.0, b: 255.0},
-                                   specular_component: Color {r: 255.0, g: 255.0, b: 255.0}}),
-    ];
+        match tokens[0] {
+            "eye" => eye = Point { x: number(1), y: number(2), z: number(3) },
+            "viewdir" => viewdir = Direction { x: number(1), y: number(2), z: number(3) },
+            "updir" => updir = Direction { x: number(1), y: number(2), z: number(3) },
+            "hfov" => hfov = number(1),
+            "imsize" => { img_width = number(1) as u32; img_height = number(2) as u32; },
+            "bkgcolor" => bkgcolor = Color { r: number(1), g: number(2), b: number(3) },
+            "mtlcolor" => {
+                //`mtlcolor r g b` keeps the default coefficients; the optional trailing
+                //`diffuse specular ambient shininess reflectivity ior` override them per material.
+                let mut material = Material::new(Color { r: number(1), g: number(2), b: number(3) });
+                if tokens.len() >= 10 {
+                    material.diffuse = number(4);
+                    material.specular = number(5);
+                    material.ambient = number(6);
+                    material.shininess = number(7);
+                    material.reflectivity = number(8);
+                    material.index_of_refraction = number(9);
+                }
+                current_material = material;
+            },
+            "light" => objects.push(Object::LightObject(Light {
+                position: Point { x: number(1), y: number(2), z: number(3) },
+                diffuse_component: Color { r: number(4), g: number(5), b: number(6) },
+                specular_component: Color { r: number(7), g: number(8), b: number(9) },
+            })),
+            "sphere" => objects.push(Object::SphereObject(Sphere {
+                center: Point { x: number(1), y: number(2), z: number(3) },
+                radius: number(4),
+                material: current_material.clone(),
+            })),
+            "plane" => objects.push(Object::PlaneObject(Plane {
+                point: Point { x: number(1), y: number(2), z: number(3) },
+                normal: Direction { x: number(4), y: number(5), z: number(6) },
+                material: current_material.clone(),
+            })),
+            "v" => vertices.push(Point { x: number(1), y: number(2), z: number(3) }),
+            "f" => {
+                //Faces reference vertices with 1-based indices.
+                let vertex_at = |index: usize| -> Point {
+                    let vertex_index = number(index) as usize;
+                    if vertex_index == 0 || vertex_index > vertices.len() {
+                        panic!("face references out-of-range vertex index in scene file line: {}", line);
+                    }
+                    return vertices[vertex_index - 1].clone();
+                };
+
+                objects.push(Object::TriangleObject(Triangle {
+                    p1: vertex_at(1), p2: vertex_at(2), p3: vertex_at(3),
+                    material: current_material.clone(),
+                }));
+            },
+            _ => {}
+        }
+    }
 
+    //The BVH only ever indexes primitives, lights are looked up directly in objects where needed.
+    let primitive_indices = objects.iter().enumerate()
+        .filter(|(_, obj)| !matches!(obj, Object::LightObject(_)))
+        .map(|(index, _)| index)
+        .collect();
+    let bvh = build_bvh(&objects, primitive_indices);
 
-    let mut img = DynamicImage::new_rgb8(IMG_WIDTH_PX, IMG_HEIGHT_PX);
+    return SceneConfig { eye, viewdir, updir, hfov, img_width, img_height, bkgcolor, objects, bvh };
+}
 
-    for view_port_pixel_x in 0..IMG_WIDTH_PX {
-        if view_port_pixel_x % progress_print_interval == 0 {
-            println!("scanline: {}", view_port_pixel_x);
-        }
 
-        let view_port_coordinate_x = (PIX_SIZE_X * view_port_pixel_x as f64) + VIEW_PORT_TOP_LEFT.x;
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let scene_path = args.get(1).expect("usage: rusting_raytracer <scene_file>");
+    let config = parse_scene_file(scene_path);
+    let camera = Camera::new(&config);
 
-        for view_port_pixel_y in 0..IMG_HEIGHT_PX {
-            let view_port_coordinate_y = (PIX_SIZE_Y * view_port_pixel_y as f64) + VIEW_PORT_TOP_LEFT.y;
+    let progress_print_interval = if config.img_width > 1000 { 100 } else { 10 };
 
-            let view_port_point = Point { x: view_port_coordinate_x.into(),
-                                          y: view_port_coordinate_y.into(),
-                                          z: CAMERA_POSITION.z + FOCAL_LENGTH };
-            let ray = ray_through_points(CAMERA_POSITION, view_port_point);
+    let mut img = DynamicImage::new_rgb8(config.img_width, config.img_height);
 
-            let color = send_ray(&scene, &ray);
+    //Scanlines are independent, so rayon hands them out to worker threads; only the final
+    //writes into `img` happen back on the main thread.
+    let scanlines: Vec<(u32, Vec<Color>)> = (0..config.img_width).into_par_iter()
+        .map(|view_port_pixel_x| {
+            if view_port_pixel_x % progress_print_interval == 0 {
+                println!("scanline: {}", view_port_pixel_x);
+            }
+
+            let colors = (0..config.img_height)
+                .map(|view_port_pixel_y| sample_pixel(&config, &camera, view_port_pixel_x, view_port_pixel_y))
+                .collect();
+
+            (view_port_pixel_x, colors)
+        })
+        .collect();
+
+    for (view_port_pixel_x, colors) in scanlines {
+        for (view_port_pixel_y, color) in colors.into_iter().enumerate() {
             let img_color = Rgba([color.r as u8, color.g as u8, color.b as u8, 0]);
-            img.put_pixel(view_port_pixel_x, view_port_pixel_y, img_color);
-        } 
+            img.put_pixel(view_port_pixel_x, view_port_pixel_y as u32, img_color);
+        }
     }
 
 